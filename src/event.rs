@@ -0,0 +1,205 @@
+//! Matrix events.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
+use diesel::{
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    OrderDsl,
+};
+use diesel::expression::dsl::any;
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::Error as DieselError;
+use ruma_events::EventType;
+use ruma_events::room::guest_access::GuestAccessEventContent;
+use ruma_events::room::history_visibility::HistoryVisibilityEventContent;
+use ruma_events::room::join_rules::JoinRulesEventContent;
+use ruma_events::room::member::{MemberEvent, MemberEventExtraContent};
+use ruma_events::room::power_levels::PowerLevelsEventContent;
+use ruma_events::stripped::StrippedState;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Error as SerdeJsonError, Value, from_value, to_value};
+
+use error::ApiError;
+use schema::events;
+
+/// A new event, not yet saved.
+#[derive(Debug, Clone)]
+#[insertable_into(events)]
+pub struct NewEvent {
+    /// The event's ID.
+    pub id: EventId,
+    /// The room the event belongs to.
+    pub room_id: RoomId,
+    /// The user who sent the event.
+    pub user_id: UserId,
+    /// The event's `type`, e.g. `m.room.member`.
+    pub event_type: String,
+    /// The event's `state_key`, empty for non-state events.
+    pub state_key: String,
+    /// The event's `content`.
+    pub content: Value,
+    /// The event's additional top-level content, e.g. `invite_room_state`.
+    pub extra_content: Option<Value>,
+}
+
+/// A Matrix event.
+#[derive(Debug, Clone, Queryable)]
+pub struct Event {
+    /// The event's ID.
+    pub id: EventId,
+    /// The room the event belongs to.
+    pub room_id: RoomId,
+    /// The user who sent the event.
+    pub user_id: UserId,
+    /// The event's `type`, e.g. `m.room.member`.
+    pub event_type: String,
+    /// The event's `state_key`, empty for non-state events.
+    pub state_key: String,
+    /// The event's `content`.
+    pub content: Value,
+    /// The event's additional top-level content, e.g. `invite_room_state`.
+    pub extra_content: Option<Value>,
+    /// The time the event was created.
+    pub created_at: PgTimestamp,
+}
+
+/// Serializes an `EventType` to the plain string Matrix uses on the wire (e.g. `m.room.member`),
+/// matching the raw-string storage convention this module already uses for `membership`.
+fn event_type_string(event_type: &EventType) -> Result<String, SerdeJsonError> {
+    Ok(to_value(event_type)?.as_str().unwrap_or_default().to_string())
+}
+
+impl TryFrom<MemberEvent> for NewEvent {
+    type Error = SerdeJsonError;
+
+    fn try_from(member_event: MemberEvent) -> Result<Self, Self::Error> {
+        Ok(NewEvent {
+            id: member_event.event_id,
+            room_id: member_event.room_id,
+            user_id: member_event.user_id,
+            event_type: event_type_string(&member_event.event_type)?,
+            state_key: member_event.state_key,
+            content: to_value(&member_event.content)?,
+            extra_content: Some(to_value(&member_event.extra_content)?),
+        })
+    }
+}
+
+impl TryFrom<Event> for MemberEvent {
+    type Error = SerdeJsonError;
+
+    fn try_from(event: Event) -> Result<Self, Self::Error> {
+        let extra_content = match event.extra_content {
+            Some(value) => from_value(value)?,
+            None => MemberEventExtraContent { invite_room_state: None },
+        };
+
+        Ok(MemberEvent {
+            content: from_value(event.content)?,
+            event_id: event.id,
+            event_type: EventType::RoomMember,
+            extra_content: extra_content,
+            prev_content: None,
+            room_id: event.room_id,
+            state_key: event.state_key,
+            unsigned: None,
+            user_id: event.user_id,
+        })
+    }
+}
+
+/// A room state event's content, typed by its purpose. Lets callers write
+/// `some_state_event.content.join_rule` without round-tripping through a full `Event`.
+#[derive(Debug, Clone)]
+pub struct StateEvent<Content> {
+    /// The typed content of the state event.
+    pub content: Content,
+}
+
+impl Event {
+    /// Return the most recent event of `event_type` in `room_id`, erroring with `not_found` if
+    /// the room has no such event yet.
+    fn find_latest_by_type(connection: &PgConnection, room_id: RoomId, event_type: EventType)
+                            -> Result<Event, ApiError> {
+        events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(event_type_string(&event_type)?))
+            .order(events::created_at.desc())
+            .first(connection)
+            .map_err(|err| match err {
+                DieselError::NotFound => ApiError::not_found(None),
+                _ => ApiError::from(err),
+            })
+    }
+
+    /// Return the room's current `m.room.join_rules` state.
+    pub fn find_room_join_rules_by_room_id(connection: &PgConnection, room_id: RoomId)
+                                            -> Result<StateEvent<JoinRulesEventContent>, ApiError> {
+        let event = Event::find_latest_by_type(connection, room_id, EventType::RoomJoinRules)?;
+        Ok(StateEvent { content: from_value(event.content)? })
+    }
+
+    /// Return the room's current `m.room.power_levels` state.
+    pub fn find_room_power_levels_by_room_id(connection: &PgConnection, room_id: RoomId)
+                                              -> Result<StateEvent<PowerLevelsEventContent>, ApiError> {
+        let event = Event::find_latest_by_type(connection, room_id, EventType::RoomPowerLevels)?;
+        Ok(StateEvent { content: from_value(event.content)? })
+    }
+
+    /// Return the room's current `m.room.guest_access` state.
+    pub fn find_room_guest_access_by_room_id(connection: &PgConnection, room_id: RoomId)
+                                              -> Result<StateEvent<GuestAccessEventContent>, ApiError> {
+        let event = Event::find_latest_by_type(connection, room_id, EventType::RoomGuestAccess)?;
+        Ok(StateEvent { content: from_value(event.content)? })
+    }
+
+    /// Return the room's current `m.room.history_visibility` state.
+    pub fn find_room_history_visibility_by_room_id(connection: &PgConnection, room_id: RoomId)
+                                                    -> Result<StateEvent<HistoryVisibilityEventContent>, ApiError> {
+        let event = Event::find_latest_by_type(connection, room_id, EventType::RoomHistoryVisibility)?;
+        Ok(StateEvent { content: from_value(event.content)? })
+    }
+
+    /// Return the latest event of each given `event_types` in `room_id`, reduced to the stripped
+    /// `type`/`state_key`/`sender`/`content` preview used for `invite_room_state`. At most one
+    /// event per `(type, state_key)` pair is returned.
+    pub fn find_stripped_state_by_room_id(connection: &PgConnection,
+                                           room_id: RoomId,
+                                           event_types: Vec<EventType>)
+                                           -> Result<Vec<StrippedState>, ApiError> {
+        let event_type_strings: Vec<String> = event_types.iter()
+            .map(event_type_string)
+            .collect::<Result<Vec<String>, SerdeJsonError>>()?;
+
+        let events: Vec<Event> = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(any(event_type_strings)))
+            .order(events::created_at.desc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let mut seen = HashSet::new();
+        let mut stripped_state = Vec::new();
+
+        for event in events {
+            if !seen.insert((event.event_type.clone(), event.state_key.clone())) {
+                continue;
+            }
+
+            let event_type: EventType = from_value(Value::String(event.event_type))?;
+
+            stripped_state.push(StrippedState {
+                content: event.content,
+                event_type: event_type,
+                sender: event.user_id,
+                state_key: event.state_key,
+            });
+        }
+
+        Ok(stripped_state)
+    }
+}
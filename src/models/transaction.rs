@@ -1,18 +1,39 @@
 //! Matrix transaction.
 
 use diesel::{
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
     FindDsl,
     LoadDsl,
+    delete,
     insert,
 };
+use diesel::expression::dsl::sql;
 use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
+use diesel::types::Bool;
+use time::Duration;
 
 use error::ApiError;
 use schema::transactions;
 
+/// A new Matrix transaction, not yet saved.
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "transactions"]
+pub struct NewTransaction {
+    /// The full path of the endpoint used for the transaction.
+    pub path: String,
+    /// The access token used.
+    pub access_token: String,
+    /// The serialized response of the endpoint. It should be used
+    /// as the response on future requests.
+    pub response: String,
+}
+
 /// A Transaction.
-#[derive(AsChangeset, Clone, Debug, Identifiable, Insertable, Queryable)]
+#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
 #[primary_key(path, access_token)]
 #[table_name = "transactions"]
 pub struct Transaction {
@@ -23,6 +44,8 @@ pub struct Transaction {
     /// The serialized response of the endpoint. It should be used
     /// as the response on future requests.
     pub response: String,
+    /// The time the transaction was created.
+    pub created_at: PgTimestamp,
 }
 
 impl Transaction {
@@ -33,7 +56,7 @@ impl Transaction {
         access_token: String,
         response: String
     ) -> Result<Transaction, ApiError> {
-        let new_transaction = Transaction {
+        let new_transaction = NewTransaction {
             path: path,
             access_token: access_token,
             response: response,
@@ -45,7 +68,8 @@ impl Transaction {
             .map_err(ApiError::from)
     }
 
-    /// Look up a transaction with the url path of the endpoint and the access token.
+    /// Look up a transaction with the url path of the endpoint and the access token, regardless
+    /// of its age.
     pub fn find(
         connection: &PgConnection,
         path: &str,
@@ -61,4 +85,68 @@ impl Transaction {
             Err(err) => Err(ApiError::from(err)),
         }
     }
+
+    /// Look up a transaction the same way as `find`, but treat entries older than `max_age` as
+    /// if they did not exist. This lets a client that reuses a transaction id long after the
+    /// original response expired get fresh processing instead of a stale replay.
+    pub fn find_fresh(
+        connection: &PgConnection,
+        path: &str,
+        access_token: &str,
+        max_age: Duration,
+    ) -> Result<Option<Transaction>, ApiError> {
+        let transaction = transactions::table
+            .filter(transactions::path.eq(path))
+            .filter(transactions::access_token.eq(access_token))
+            .filter(sql::<Bool>(&Transaction::not_older_than_clause(max_age)))
+            .first(connection);
+
+        match transaction {
+            Ok(transaction) => Ok(Some(transaction)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Delete all transaction entries older than `older_than`. Intended to be run periodically
+    /// so the `transactions` table does not grow without bound.
+    pub fn prune(connection: &PgConnection, older_than: Duration) -> Result<usize, ApiError> {
+        delete(transactions::table.filter(sql::<Bool>(&Transaction::older_than_clause(older_than))))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Builds the raw SQL clause used by `find_fresh` to keep only entries created within the
+    /// last `max_age`.
+    fn not_older_than_clause(max_age: Duration) -> String {
+        format!("created_at > now() - interval '{} seconds'", max_age.num_seconds())
+    }
+
+    /// Builds the raw SQL clause used by `prune` to select entries older than `older_than`.
+    fn older_than_clause(older_than: Duration) -> String {
+        format!("created_at < now() - interval '{} seconds'", older_than.num_seconds())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use super::Transaction;
+
+    #[test]
+    fn not_older_than_clause_embeds_the_max_age_in_seconds() {
+        assert_eq!(
+            Transaction::not_older_than_clause(Duration::minutes(5)),
+            "created_at > now() - interval '300 seconds'"
+        );
+    }
+
+    #[test]
+    fn older_than_clause_embeds_the_cutoff_in_seconds() {
+        assert_eq!(
+            Transaction::older_than_clause(Duration::hours(1)),
+            "created_at < now() - interval '3600 seconds'"
+        );
+    }
 }
@@ -17,6 +17,8 @@ use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
 use ruma_events::EventType;
+use ruma_events::room::guest_access::GuestAccess;
+use ruma_events::room::history_visibility::HistoryVisibility;
 use ruma_events::room::join_rules::JoinRule;
 use ruma_events::room::member::{
     MemberEvent,
@@ -24,6 +26,8 @@ use ruma_events::room::member::{
     MemberEventContent,
     MemberEventExtraContent
 };
+use ruma_events::room::power_levels::PowerLevelsEventContent;
+use ruma_events::stripped::StrippedState;
 use ruma_identifiers::{EventId, RoomId, UserId};
 use serde_json::{Error as SerdeJsonError, Value, from_value};
 
@@ -32,6 +36,17 @@ use event::{NewEvent, Event};
 use profile::Profile;
 use schema::{events, room_memberships};
 
+/// The state event types that make up the stripped preview sent to an invited user as
+/// `invite_room_state`.
+const INVITE_ROOM_STATE_EVENT_TYPES: &'static [EventType] = &[
+    EventType::RoomCreate,
+    EventType::RoomName,
+    EventType::RoomAvatar,
+    EventType::RoomCanonicalAlias,
+    EventType::RoomJoinRules,
+    EventType::RoomEncryption,
+];
+
 /// Room membership update or create data.
 #[derive(Debug, Clone)]
 pub struct RoomMembershipOptions {
@@ -41,7 +56,7 @@ pub struct RoomMembershipOptions {
     pub user_id: UserId,
     /// The ID of the user who created the membership.
     pub sender: UserId,
-    /// The current membership state.
+    /// The current membership state, one of `join`, `invite`, `leave`, `ban`, or `knock`.
     pub membership: String,
 }
 
@@ -97,15 +112,119 @@ impl RoomMembership {
                 room_membership_options.clone().room_id
             )?;
 
+            let requested_membership: MembershipState =
+                from_value(Value::String(room_membership_options.clone().membership))?;
+
             match room_membership {
-                Some(room_membership) => Ok(room_membership),
+                Some(mut room_membership) => {
+                    let current_membership: MembershipState =
+                        from_value(Value::String(room_membership.clone().membership))?;
+
+                    // Nothing to do if the membership isn't actually changing.
+                    if current_membership == requested_membership {
+                        return Ok(room_membership);
+                    }
+
+                    let power_levels_event = Event::find_room_power_levels_by_room_id(
+                        &connection,
+                        room_membership_options.clone().room_id
+                    )?;
+
+                    let is_invited = current_membership == MembershipState::Invite;
+
+                    RoomMembership::ensure_transition_is_authorized(
+                        &power_levels_event.content,
+                        &room_membership_options.sender,
+                        &room_membership_options.user_id,
+                        Some(current_membership),
+                        &requested_membership,
+                    )?;
+
+                    // `ensure_transition_is_authorized` lets a user always re-join on their own
+                    // behalf, but that is an authorization check, not a join-rule check: a user
+                    // who has left (or been kicked from) an invite-only or knock-restricted room
+                    // still needs a fresh invite to get back in. A user who already has a
+                    // membership row cannot knock again, since `ensure_transition_is_authorized`
+                    // refuses a self-`knock`.
+                    if room_membership_options.sender == room_membership_options.user_id &&
+                       requested_membership == MembershipState::Join && !is_invited {
+                        RoomMembership::ensure_self_creation_is_permitted(
+                            join_rules_event.content.join_rule.clone(),
+                            &requested_membership,
+                        )?;
+                    }
+
+                    room_membership.membership = room_membership_options.clone().membership;
+                    room_membership.sender = room_membership_options.clone().sender;
+
+                    let profile = Profile::find_by_user_id(connection, room_membership_options.clone().user_id)?;
+                    let avatar_url = match profile.clone() {
+                        Some(profile) => profile.avatar_url,
+                        None => None,
+                    };
+                    let displayname = match profile {
+                        Some(profile) => profile.displayname,
+                        None => None,
+                    };
+
+                    let event_id = EventId::new(&homeserver_domain).map_err(ApiError::from)?;
+
+                    let invite_room_state = RoomMembership::invite_room_state(
+                        connection,
+                        room_membership_options.clone().room_id,
+                        &requested_membership,
+                    )?;
+
+                    let new_memberstate_event: NewEvent = MemberEvent {
+                        content: MemberEventContent {
+                            avatar_url: avatar_url,
+                            displayname: displayname,
+                            membership: requested_membership,
+                            third_party_invite: (),
+                        },
+                        event_id: event_id.clone(),
+                        event_type: EventType::RoomMember,
+                        extra_content: MemberEventExtraContent { invite_room_state: invite_room_state },
+                        prev_content: None,
+                        room_id: room_membership_options.clone().room_id,
+                        state_key: "".to_string(),
+                        unsigned: None,
+                        user_id: room_membership_options.clone().user_id,
+                    }.try_into()?;
+
+                    insert(&new_memberstate_event).into(events::table)
+                        .execute(connection)
+                        .map_err(ApiError::from)?;
+
+                    room_membership.update(connection, event_id)?;
+
+                    Ok(room_membership)
+                }
                 None => {
-                    // If there is no membership entry for the current user and
-                    // the room is invite-only, no membership entry can be created for that user.
-                    // Unless it's the owner of the room.
-                    if room_membership_options.user_id != room_membership_options.sender &&
-                       join_rules_event.content.join_rule == JoinRule::Invite {
-                        return Err(ApiError::unauthorized(Some("You are not invited to this room.")));
+                    // If there is no membership entry for the target user, a sender acting on
+                    // someone else's behalf (inviting, or any other cross-user membership) is
+                    // always subject to the room's power levels, regardless of join rule — a
+                    // public room does not let an unprivileged third party plant an arbitrary
+                    // membership (e.g. a ban) on another user who has never joined.
+                    if room_membership_options.user_id != room_membership_options.sender {
+                        let power_levels_event = Event::find_room_power_levels_by_room_id(
+                            &connection,
+                            room_membership_options.clone().room_id
+                        )?;
+
+                        RoomMembership::ensure_transition_is_authorized(
+                            &power_levels_event.content,
+                            &room_membership_options.sender,
+                            &room_membership_options.user_id,
+                            None,
+                            &requested_membership,
+                        )?;
+                    } else {
+                        // The user has no prior membership and is creating it for themselves.
+                        RoomMembership::ensure_self_creation_is_permitted(
+                            join_rules_event.content.join_rule.clone(),
+                            &requested_membership,
+                        )?;
                     }
 
                     let event_id = EventId::new(&homeserver_domain).map_err(ApiError::from)?;
@@ -131,6 +250,12 @@ impl RoomMembership {
                         None => None,
                     };
 
+                    let invite_room_state = RoomMembership::invite_room_state(
+                        connection,
+                        room_membership_options.clone().room_id,
+                        &membership,
+                    )?;
+
                     let new_memberstate_event: NewEvent = MemberEvent {
                         content: MemberEventContent {
                             avatar_url: avatar_url,
@@ -140,7 +265,7 @@ impl RoomMembership {
                         },
                         event_id: event_id.clone(),
                         event_type: EventType::RoomMember,
-                        extra_content: MemberEventExtraContent { invite_room_state: None },
+                        extra_content: MemberEventExtraContent { invite_room_state: invite_room_state },
                         prev_content: None,
                         room_id: room_membership_options.clone().room_id,
                         state_key: "".to_string(),
@@ -173,6 +298,12 @@ impl RoomMembership {
         let membership_string = Value::String(room_membership.clone().membership);
         let membership: MembershipState = from_value(membership_string)?;
 
+        let invite_room_state = RoomMembership::invite_room_state(
+            connection,
+            room_membership.clone().room_id,
+            &membership,
+        )?;
+
         let new_memberstate_event: NewEvent = MemberEvent {
             content: MemberEventContent {
                 avatar_url: profile.avatar_url,
@@ -182,7 +313,7 @@ impl RoomMembership {
             },
             event_id: event_id.clone(),
             event_type: EventType::RoomMember,
-            extra_content: MemberEventExtraContent { invite_room_state: None },
+            extra_content: MemberEventExtraContent { invite_room_state: invite_room_state },
             prev_content: None,
             room_id: room_membership.clone().room_id,
             state_key: "".to_string(),
@@ -200,17 +331,126 @@ impl RoomMembership {
     }
 
 
-    /// Update a `RoomMembership` entry.
+    /// Update a `RoomMembership` entry, persisting the event pointer along with whatever
+    /// `membership` and `sender` this in-memory struct currently holds. Callers that change
+    /// `membership` (e.g. a ban/kick/invite transition) must assign the new value before calling
+    /// this, or the authoritative row will keep stale data even though a new member event exists.
     fn update(&mut self, connection: &PgConnection, event_id: EventId) -> Result<(), ApiError> {
         let room_memberships = room_memberships::table
             .filter(room_memberships::room_id.eq(self.clone().room_id))
             .filter(room_memberships::user_id.eq(self.clone().user_id));
         update(room_memberships)
-            .set(room_memberships::event_id.eq(event_id))
+            .set((
+                room_memberships::event_id.eq(event_id),
+                room_memberships::membership.eq(self.clone().membership),
+                room_memberships::sender.eq(self.clone().sender),
+            ))
             .execute(connection)?;
         Ok(())
     }
 
+    /// Builds the `invite_room_state` to attach to a member event, giving invited users a
+    /// stripped-down preview of the room. Returns `None` unless `membership` is `invite`.
+    fn invite_room_state(connection: &PgConnection, room_id: RoomId, membership: &MembershipState)
+                          -> Result<Option<Vec<StrippedState>>, ApiError> {
+        if !RoomMembership::needs_invite_room_state(membership) {
+            return Ok(None);
+        }
+
+        let stripped_state = Event::find_stripped_state_by_room_id(
+            connection,
+            room_id,
+            INVITE_ROOM_STATE_EVENT_TYPES.to_vec(),
+        )?;
+
+        Ok(Some(stripped_state))
+    }
+
+    /// Whether a member event being written with the given `membership` should carry an
+    /// `invite_room_state` preview; only true for `invite`.
+    fn needs_invite_room_state(membership: &MembershipState) -> bool {
+        *membership == MembershipState::Invite
+    }
+
+    /// Ensures a user with no prior membership may create their own membership in a room with
+    /// the given `join_rule`. An invite-only room can never be self-joined; a knock-restricted
+    /// room can only be self-joined by knocking.
+    fn ensure_self_creation_is_permitted(join_rule: JoinRule, requested: &MembershipState)
+                                          -> Result<(), ApiError> {
+        match join_rule {
+            JoinRule::Invite => {
+                Err(ApiError::unauthorized(Some("You are not invited to this room.")))
+            }
+            JoinRule::Knock if *requested != MembershipState::Knock => {
+                Err(ApiError::unauthorized(
+                    Some("This room can only be joined by invitation or by knocking.")
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Ensures `sender` is permitted, under the room's power levels, to move `target` from
+    /// `current` (absent if `target` has no prior membership) to `requested`.
+    ///
+    /// Users may always set their own membership to `leave` or `join`, unless they are currently
+    /// banned, in which case only a sufficiently privileged `sender` may unban them. A self-`join`
+    /// is still subject to the room's join rules, which are checked separately by the caller.
+    /// Every other transition is authorized according to the `ban`, `kick`, and `invite` power
+    /// level requirements defined by the room's `m.room.power_levels` event.
+    fn ensure_transition_is_authorized(power_levels: &PowerLevelsEventContent,
+                                        sender: &UserId,
+                                        target: &UserId,
+                                        current: Option<MembershipState>,
+                                        requested: &MembershipState)
+                                        -> Result<(), ApiError> {
+        // A user may always (re)join on their own behalf, and may always leave on their own
+        // behalf *unless* they are currently banned — banning requires `kick` level to undo,
+        // even when the banned user is acting on themselves.
+        if sender == target && current != Some(MembershipState::Ban) {
+            if *requested == MembershipState::Join || *requested == MembershipState::Leave {
+                return Ok(());
+            }
+        }
+
+        let sender_level = *power_levels.users.get(sender).unwrap_or(&power_levels.users_default);
+        let target_level = *power_levels.users.get(target).unwrap_or(&power_levels.users_default);
+
+        match *requested {
+            MembershipState::Ban => {
+                if sender_level >= power_levels.ban && sender_level > target_level {
+                    Ok(())
+                } else {
+                    Err(ApiError::unauthorized(Some("You do not have permission to ban this user.")))
+                }
+            }
+            MembershipState::Leave if current == Some(MembershipState::Ban) => {
+                // Unbanning.
+                if sender_level >= power_levels.kick {
+                    Ok(())
+                } else {
+                    Err(ApiError::unauthorized(Some("You do not have permission to unban this user.")))
+                }
+            }
+            MembershipState::Leave => {
+                // Kicking.
+                if sender_level >= power_levels.kick && sender_level > target_level {
+                    Ok(())
+                } else {
+                    Err(ApiError::unauthorized(Some("You do not have permission to kick this user.")))
+                }
+            }
+            MembershipState::Invite => {
+                if sender_level >= power_levels.invite {
+                    Ok(())
+                } else {
+                    Err(ApiError::unauthorized(Some("You do not have permission to invite this user.")))
+                }
+            }
+            _ => Err(ApiError::unauthorized(Some("This membership transition is not allowed."))),
+        }
+    }
+
     /// Return `RoomMembership`'s for given `UserId`.
     pub fn find_by_user_id(connection: &PgConnection, user_id: UserId) -> Result<Vec<RoomMembership>, ApiError> {
         let room_memberships: Vec<RoomMembership> = room_memberships::table
@@ -238,12 +478,32 @@ impl RoomMembership {
         }
     }
 
-    /// Return member event's for given `room_id`.
-    pub fn get_events_by_room(connection: &PgConnection, room_id: RoomId) -> Result<Vec<MemberEvent>, ApiError> {
+    /// Return member event's for given `room_id`, including pending `knock` memberships.
+    ///
+    /// Results are filtered according to the room's `m.room.history_visibility` and
+    /// `m.room.guest_access`: a guest is refused entirely when guest access is `forbidden`, and
+    /// the member events returned to `requester` depend on history visibility as described by
+    /// `visibility_for`. As an exception to that filter, a `requester` with at least `kick`
+    /// level (a room moderator) always sees pending `knock` member events, so they can act on
+    /// them even under a history visibility that would otherwise hide the knocking user.
+    pub fn get_events_by_room(connection: &PgConnection,
+                               room_id: RoomId,
+                               requester: &UserId,
+                               requester_is_guest: bool)
+                               -> Result<Vec<MemberEvent>, ApiError> {
+        let guest_access_event = Event::find_room_guest_access_by_room_id(
+            &connection,
+            room_id.clone()
+        )?;
+
+        if requester_is_guest && guest_access_event.content.guest_access == GuestAccess::Forbidden {
+            return Err(ApiError::unauthorized(Some("Guests are not allowed in this room.")));
+        }
+
         let event_ids = room_memberships::table
-            .filter(room_memberships::room_id.eq(room_id))
+            .filter(room_memberships::room_id.eq(room_id.clone()))
             .select(room_memberships::event_id);
-        let events: Vec<Event> = events::table
+        let mut events: Vec<Event> = events::table
             .filter(events::id.eq(any(event_ids)))
             .get_results(connection)
             .map_err(|err| match err {
@@ -251,7 +511,344 @@ impl RoomMembership {
                 _ => ApiError::from(err),
             })?;
 
+        let history_visibility_event = Event::find_room_history_visibility_by_room_id(
+            &connection,
+            room_id.clone()
+        )?;
+
+        let requester_membership = RoomMembership::find(connection, &room_id, requester)?;
+
+        let power_levels_event = Event::find_room_power_levels_by_room_id(&connection, room_id.clone())?;
+        let requester_is_moderator = RoomMembership::is_moderator(&power_levels_event.content, requester);
+
+        let knock_event_ids: Vec<EventId> = if requester_is_moderator {
+            room_memberships::table
+                .filter(room_memberships::room_id.eq(room_id.clone()))
+                .filter(room_memberships::membership.eq("knock"))
+                .select(room_memberships::event_id)
+                .get_results(connection)
+                .map_err(ApiError::from)?
+        } else {
+            Vec::new()
+        };
+
+        match RoomMembership::visibility_for(
+            &history_visibility_event.content.history_visibility,
+            &requester_membership,
+        ) {
+            Visibility::All => {}
+            Visibility::Since(visible_since) => {
+                events.retain(|event| event.created_at >= visible_since || knock_event_ids.contains(&event.id));
+            }
+            Visibility::None => events.retain(|event| knock_event_ids.contains(&event.id)),
+        }
+
         let member_events: Result<Vec<MemberEvent>, SerdeJsonError> = events.into_iter().map(TryInto::try_into).collect();
         member_events.map_err(ApiError::from)
     }
+
+    /// Whether `user_id` holds at least `kick` level in the room, the bar this module uses to
+    /// decide who counts as a moderator for purposes like seeing pending `knock` requests.
+    fn is_moderator(power_levels: &PowerLevelsEventContent, user_id: &UserId) -> bool {
+        let level = *power_levels.users.get(user_id).unwrap_or(&power_levels.users_default);
+        level >= power_levels.kick
+    }
+
+    /// Determines how much of a room's membership history `requester_membership` (the
+    /// requester's own `RoomMembership`, if any) is allowed to see under `history_visibility`.
+    ///
+    /// `world_readable` is visible to everyone; `shared` is visible in full to anyone who has
+    /// ever had a membership row in the room, past or present; `invited` is visible, from the
+    /// requester's own `created_at` onward, to anyone currently joined or invited; `joined` is
+    /// the same but restricted to requesters who are currently joined. Note that `created_at` is
+    /// set once, when a user's first membership row is created, so for a user who was invited
+    /// before joining it marks the invite rather than the later join — an approximation of the
+    /// `joined` cutoff imposed by not tracking a timestamp per transition.
+    fn visibility_for(history_visibility: &HistoryVisibility,
+                       requester_membership: &Option<RoomMembership>)
+                       -> Visibility {
+        if *history_visibility == HistoryVisibility::WorldReadable {
+            return Visibility::All;
+        }
+
+        let requester_membership = match *requester_membership {
+            Some(ref membership) => membership,
+            None => return Visibility::None,
+        };
+
+        if *history_visibility == HistoryVisibility::Shared {
+            return Visibility::All;
+        }
+
+        let state: MembershipState = match from_value(Value::String(requester_membership.clone().membership)) {
+            Ok(state) => state,
+            Err(_) => return Visibility::None,
+        };
+
+        let is_visible = match *history_visibility {
+            HistoryVisibility::Invited => state == MembershipState::Join || state == MembershipState::Invite,
+            HistoryVisibility::Joined => state == MembershipState::Join,
+            HistoryVisibility::WorldReadable | HistoryVisibility::Shared => unreachable!(),
+        };
+
+        if is_visible {
+            Visibility::Since(requester_membership.created_at)
+        } else {
+            Visibility::None
+        }
+    }
+}
+
+/// The slice of a room's membership history a requester is allowed to see, per
+/// `RoomMembership::visibility_for`.
+enum Visibility {
+    /// The full membership history is visible.
+    All,
+    /// Only membership events created at or after this time are visible.
+    Since(PgTimestamp),
+    /// No membership history is visible.
+    None,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+
+    use diesel::pg::data_types::PgTimestamp;
+    use ruma_events::room::history_visibility::HistoryVisibility;
+    use ruma_events::room::join_rules::JoinRule;
+    use ruma_events::room::member::MembershipState;
+    use ruma_events::room::power_levels::PowerLevelsEventContent;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+
+    use super::{RoomMembership, Visibility};
+
+    fn membership_row(membership: &str, created_at: i64) -> RoomMembership {
+        RoomMembership {
+            event_id: EventId::try_from("$event:example.com").unwrap(),
+            room_id: RoomId::try_from("!room:example.com").unwrap(),
+            user_id: UserId::try_from("@alice:example.com").unwrap(),
+            sender: UserId::try_from("@alice:example.com").unwrap(),
+            membership: membership.to_string(),
+            created_at: PgTimestamp(created_at),
+        }
+    }
+
+    fn assert_since(visibility: Visibility, expected: i64) {
+        match visibility {
+            Visibility::Since(PgTimestamp(created_at)) => assert_eq!(created_at, expected),
+            _ => panic!("expected Visibility::Since({})", expected),
+        }
+    }
+
+    #[test]
+    fn self_creation_is_denied_on_invite_only_rooms() {
+        assert!(RoomMembership::ensure_self_creation_is_permitted(
+            JoinRule::Invite, &MembershipState::Join
+        ).is_err());
+    }
+
+    #[test]
+    fn self_creation_as_knock_is_allowed_on_knock_restricted_rooms() {
+        assert!(RoomMembership::ensure_self_creation_is_permitted(
+            JoinRule::Knock, &MembershipState::Knock
+        ).is_ok());
+    }
+
+    #[test]
+    fn self_creation_as_join_is_denied_on_knock_restricted_rooms() {
+        assert!(RoomMembership::ensure_self_creation_is_permitted(
+            JoinRule::Knock, &MembershipState::Join
+        ).is_err());
+    }
+
+    #[test]
+    fn self_creation_as_join_is_allowed_on_public_rooms() {
+        assert!(RoomMembership::ensure_self_creation_is_permitted(
+            JoinRule::Public, &MembershipState::Join
+        ).is_ok());
+    }
+
+    #[test]
+    fn only_invite_membership_needs_invite_room_state() {
+        assert!(RoomMembership::needs_invite_room_state(&MembershipState::Invite));
+        assert!(!RoomMembership::needs_invite_room_state(&MembershipState::Join));
+        assert!(!RoomMembership::needs_invite_room_state(&MembershipState::Leave));
+        assert!(!RoomMembership::needs_invite_room_state(&MembershipState::Ban));
+        assert!(!RoomMembership::needs_invite_room_state(&MembershipState::Knock));
+    }
+
+    fn power_levels(users: Vec<(&str, i64)>) -> PowerLevelsEventContent {
+        PowerLevelsEventContent {
+            ban: 50,
+            events: HashMap::new(),
+            events_default: 0,
+            invite: 50,
+            kick: 50,
+            redact: 50,
+            state_default: 50,
+            users: users.into_iter()
+                .map(|(user_id, level)| (UserId::try_from(user_id).unwrap(), level))
+                .collect(),
+            users_default: 0,
+        }
+    }
+
+    #[test]
+    fn ban_requires_ban_level_and_outranking_the_target() {
+        let power_levels = power_levels(vec![("@mod:example.com", 50), ("@troll:example.com", 0)]);
+        let mod_id = UserId::try_from("@mod:example.com").unwrap();
+        let troll_id = UserId::try_from("@troll:example.com").unwrap();
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &mod_id, &troll_id, Some(MembershipState::Join), &MembershipState::Ban
+        ).is_ok());
+    }
+
+    #[test]
+    fn ban_is_denied_below_ban_level() {
+        let power_levels = power_levels(vec![("@member:example.com", 0), ("@troll:example.com", 0)]);
+        let member_id = UserId::try_from("@member:example.com").unwrap();
+        let troll_id = UserId::try_from("@troll:example.com").unwrap();
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &member_id, &troll_id, Some(MembershipState::Join), &MembershipState::Ban
+        ).is_err());
+    }
+
+    #[test]
+    fn ban_is_denied_against_an_equal_or_higher_level_user() {
+        let power_levels = power_levels(vec![("@mod:example.com", 50), ("@other_mod:example.com", 50)]);
+        let mod_id = UserId::try_from("@mod:example.com").unwrap();
+        let other_mod_id = UserId::try_from("@other_mod:example.com").unwrap();
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &mod_id, &other_mod_id, Some(MembershipState::Join), &MembershipState::Ban
+        ).is_err());
+    }
+
+    #[test]
+    fn kick_requires_kick_level_and_outranking_the_target() {
+        let power_levels = power_levels(vec![("@mod:example.com", 50), ("@member:example.com", 0)]);
+        let mod_id = UserId::try_from("@mod:example.com").unwrap();
+        let member_id = UserId::try_from("@member:example.com").unwrap();
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &mod_id, &member_id, Some(MembershipState::Join), &MembershipState::Leave
+        ).is_ok());
+    }
+
+    #[test]
+    fn unban_requires_kick_level_only() {
+        let power_levels = power_levels(vec![("@mod:example.com", 50), ("@troll:example.com", 0)]);
+        let mod_id = UserId::try_from("@mod:example.com").unwrap();
+        let troll_id = UserId::try_from("@troll:example.com").unwrap();
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &mod_id, &troll_id, Some(MembershipState::Ban), &MembershipState::Leave
+        ).is_ok());
+    }
+
+    #[test]
+    fn unban_is_denied_below_kick_level() {
+        let power_levels = power_levels(vec![("@member:example.com", 0), ("@troll:example.com", 0)]);
+        let member_id = UserId::try_from("@member:example.com").unwrap();
+        let troll_id = UserId::try_from("@troll:example.com").unwrap();
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &member_id, &troll_id, Some(MembershipState::Ban), &MembershipState::Leave
+        ).is_err());
+    }
+
+    #[test]
+    fn invite_requires_invite_level() {
+        let power_levels = power_levels(vec![("@member:example.com", 0)]);
+        let member_id = UserId::try_from("@member:example.com").unwrap();
+        let stranger_id = UserId::try_from("@stranger:example.com").unwrap();
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &member_id, &stranger_id, None, &MembershipState::Invite
+        ).is_err());
+    }
+
+    #[test]
+    fn is_moderator_requires_at_least_kick_level() {
+        let power_levels = power_levels(vec![("@mod:example.com", 50), ("@member:example.com", 0)]);
+        let mod_id = UserId::try_from("@mod:example.com").unwrap();
+        let member_id = UserId::try_from("@member:example.com").unwrap();
+
+        assert!(RoomMembership::is_moderator(&power_levels, &mod_id));
+        assert!(!RoomMembership::is_moderator(&power_levels, &member_id));
+    }
+
+    #[test]
+    fn a_user_may_always_join_or_leave_on_their_own_behalf() {
+        let power_levels = power_levels(vec![]);
+        let user_id = UserId::try_from("@alice:example.com").unwrap();
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &user_id, &user_id, None, &MembershipState::Join
+        ).is_ok());
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &user_id, &user_id, Some(MembershipState::Join), &MembershipState::Leave
+        ).is_ok());
+    }
+
+    #[test]
+    fn a_banned_user_cannot_self_unban() {
+        let power_levels = power_levels(vec![]);
+        let user_id = UserId::try_from("@alice:example.com").unwrap();
+
+        assert!(RoomMembership::ensure_transition_is_authorized(
+            &power_levels, &user_id, &user_id, Some(MembershipState::Ban), &MembershipState::Leave
+        ).is_err());
+    }
+
+    #[test]
+    fn world_readable_is_visible_to_anyone_including_non_members() {
+        let visibility = RoomMembership::visibility_for(&HistoryVisibility::WorldReadable, &None);
+        assert!(match visibility { Visibility::All => true, _ => false });
+    }
+
+    #[test]
+    fn shared_is_visible_in_full_to_a_past_or_present_member() {
+        let membership = membership_row("leave", 100);
+        let visibility = RoomMembership::visibility_for(&HistoryVisibility::Shared, &Some(membership));
+        assert!(match visibility { Visibility::All => true, _ => false });
+    }
+
+    #[test]
+    fn shared_is_hidden_from_a_non_member() {
+        let visibility = RoomMembership::visibility_for(&HistoryVisibility::Shared, &None);
+        assert!(match visibility { Visibility::None => true, _ => false });
+    }
+
+    #[test]
+    fn invited_is_visible_since_created_at_to_an_invited_user() {
+        let membership = membership_row("invite", 100);
+        let visibility = RoomMembership::visibility_for(&HistoryVisibility::Invited, &Some(membership));
+        assert_since(visibility, 100);
+    }
+
+    #[test]
+    fn joined_is_hidden_from_a_merely_invited_user() {
+        let membership = membership_row("invite", 100);
+        let visibility = RoomMembership::visibility_for(&HistoryVisibility::Joined, &Some(membership));
+        assert!(match visibility { Visibility::None => true, _ => false });
+    }
+
+    #[test]
+    fn joined_is_visible_since_created_at_to_a_joined_user() {
+        let membership = membership_row("join", 100);
+        let visibility = RoomMembership::visibility_for(&HistoryVisibility::Joined, &Some(membership));
+        assert_since(visibility, 100);
+    }
+
+    #[test]
+    fn non_world_readable_visibility_is_hidden_from_a_non_member() {
+        let visibility = RoomMembership::visibility_for(&HistoryVisibility::Invited, &None);
+        assert!(match visibility { Visibility::None => true, _ => false });
+    }
 }
\ No newline at end of file